@@ -0,0 +1,85 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use ethcore::client::BlockId;
+use ethkey::public_to_address;
+use native_contracts::SecretStoreAclStorage;
+use futures::{future, Future};
+use ethkey::Public;
+use trusted_client::SecretStoreChain;
+use ServerKeyId;
+
+/// Name of the SecretStore ACL storage contract in the registry.
+const ACL_STORAGE_CONTRACT_REGISTRY_NAME: &'static str = "secretstore_acl_storage";
+
+/// ACL storage: decides whether a given requester is allowed to access a given document key.
+pub trait AclStorage: Send + Sync {
+	/// Check if `requester` is permitted to access `document`.
+	fn check(&self, requester: Public, document: &ServerKeyId) -> Result<bool, String>;
+}
+
+/// On-chain ACL storage, backed by the `secretstore_acl_storage` registry contract.
+pub struct OnChainAclStorage {
+	/// Blockchain access, narrowed down to what this contract layer needs.
+	chain: Arc<SecretStoreChain>,
+}
+
+impl OnChainAclStorage {
+	/// Create new on-chain ACL storage.
+	pub fn new(chain: Arc<SecretStoreChain>) -> Self {
+		OnChainAclStorage {
+			chain: chain,
+		}
+	}
+
+	/// Resolve the ACL storage contract address from the registry.
+	fn contract_address(&self) -> Option<::util::Address> {
+		self.chain.registry_address(ACL_STORAGE_CONTRACT_REGISTRY_NAME.to_owned())
+	}
+}
+
+impl AclStorage for OnChainAclStorage {
+	fn check(&self, requester: Public, document: &ServerKeyId) -> Result<bool, String> {
+		let contract_address = self.contract_address()
+			.ok_or_else(|| "ACL storage contract is not installed".to_owned())?;
+		let contract = SecretStoreAclStorage::new(contract_address);
+		let requester = public_to_address(&requester);
+		let do_call = |a, d| future::done(self.chain.call_contract(BlockId::Latest, a, d));
+		contract.check_permissions(&do_call, requester, document.clone()).wait()
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use std::collections::HashSet;
+	use ethkey::Public;
+	use ServerKeyId;
+	use super::AclStorage;
+
+	/// ACL storage mock, granting access to every (requester, document) pair except those in
+	/// the explicit `prohibited` deny-list, used to unit-test callers without a full on-chain contract.
+	#[derive(Default)]
+	pub struct DummyAclStorage {
+		pub prohibited: HashSet<(Public, ServerKeyId)>,
+	}
+
+	impl AclStorage for DummyAclStorage {
+		fn check(&self, requester: Public, document: &ServerKeyId) -> Result<bool, String> {
+			Ok(!self.prohibited.contains(&(requester, document.clone())))
+		}
+	}
+}
@@ -0,0 +1,113 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use ethcore::client::BlockId;
+use ethkey::Public;
+use native_contracts::SecretStoreKeyServerSet;
+use futures::{future, Future};
+use parking_lot::RwLock;
+use trusted_client::SecretStoreChain;
+
+/// Name of the SecretStore key server set contract in the registry.
+const KEY_SERVER_SET_CONTRACT_REGISTRY_NAME: &'static str = "secretstore_key_server_set";
+
+/// The set of key servers that make up the cluster this node participates in.
+pub trait KeyServerSet: Send + Sync {
+	/// Current key server set: node id -> network address.
+	fn get(&self) -> BTreeMap<Public, SocketAddr>;
+}
+
+/// On-chain key server set, backed by the `secretstore_key_server_set` registry contract.
+/// Caches the last read set so `get()` does not need a contract call on every use; `update()`
+/// refreshes the cache and should be called on every `ChainNotify` update, same as
+/// `OnChainServiceContract::update`.
+pub struct OnChainKeyServerSet {
+	/// Blockchain access, narrowed down to what this contract layer needs.
+	chain: Arc<SecretStoreChain>,
+	/// Cached key server set.
+	key_servers: RwLock<BTreeMap<Public, SocketAddr>>,
+}
+
+impl OnChainKeyServerSet {
+	/// Create new on-chain key server set, performing an initial read if the chain is trusted.
+	pub fn new(chain: Arc<SecretStoreChain>) -> Self {
+		let key_server_set = OnChainKeyServerSet {
+			chain: chain,
+			key_servers: RwLock::new(BTreeMap::new()),
+		};
+		key_server_set.update();
+		key_server_set
+	}
+
+	/// Resolve the key server set contract address from the registry.
+	fn contract_address(&self) -> Option<::util::Address> {
+		self.chain.registry_address(KEY_SERVER_SET_CONTRACT_REGISTRY_NAME.to_owned())
+	}
+
+	/// Re-read the key server set from the contract, if it is installed and the chain is
+	/// trusted; otherwise leave the cached set untouched.
+	pub fn update(&self) {
+		if !self.chain.is_trusted() {
+			return;
+		}
+
+		let contract_address = match self.contract_address() {
+			Some(address) => address,
+			None => return,
+		};
+
+		let contract = SecretStoreKeyServerSet::new(contract_address);
+		let do_call = |a, d| future::done(self.chain.call_contract(BlockId::Latest, a, d));
+		let key_servers = contract.get_key_servers(&do_call).wait()
+			.map_err(|error| warn!(target: "secretstore", "failed to read key server set: {}", error))
+			.unwrap_or_default()
+			.into_iter()
+			.filter_map(|(public, address)| SocketAddr::from_str(&address).ok().map(|address| (public, address)))
+			.collect();
+
+		*self.key_servers.write() = key_servers;
+	}
+}
+
+impl KeyServerSet for OnChainKeyServerSet {
+	fn get(&self) -> BTreeMap<Public, SocketAddr> {
+		self.key_servers.read().clone()
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use std::collections::BTreeMap;
+	use std::net::SocketAddr;
+	use ethkey::Public;
+	use super::KeyServerSet;
+
+	/// Key server set mock, used to unit-test callers without a full on-chain contract.
+	#[derive(Default)]
+	pub struct DummyKeyServerSet {
+		pub key_servers: BTreeMap<Public, SocketAddr>,
+	}
+
+	impl KeyServerSet for DummyKeyServerSet {
+		fn get(&self) -> BTreeMap<Public, SocketAddr> {
+			self.key_servers.clone()
+		}
+	}
+}
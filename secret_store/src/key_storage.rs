@@ -0,0 +1,65 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use bigint::hash::H256;
+use ServerKeyId;
+
+/// Persistent storage for generated server keys and service-contract scanning progress.
+pub trait KeyStorage: Send + Sync {
+	/// Does the storage contain a key for `server_key_id`?
+	fn contains(&self, server_key_id: &ServerKeyId) -> bool;
+
+	/// Read the last block hash whose service contract logs were fully, durably processed.
+	/// Returns `Ok(None)` if no block has been processed yet (e.g. on a fresh node), so
+	/// scanning can start from the current block instead of replaying the whole chain.
+	fn last_processed_block(&self) -> Result<Option<H256>, String>;
+
+	/// Persist `block` as the last block whose service contract logs were fully, durably
+	/// processed. Called only once the caller has durably handled everything read from that
+	/// block, so that a crash never loses a request that was read but not yet acted upon.
+	fn update_last_processed_block(&self, block: H256) -> Result<(), String>;
+}
+
+#[cfg(test)]
+pub mod tests {
+	use std::sync::Mutex;
+	use std::collections::HashSet;
+	use bigint::hash::H256;
+	use ServerKeyId;
+	use super::KeyStorage;
+
+	/// In-memory key storage mock, used to unit-test the contract layer without a real database.
+	#[derive(Default)]
+	pub struct DummyKeyStorage {
+		pub keys: HashSet<ServerKeyId>,
+		pub last_processed_block: Mutex<Option<H256>>,
+	}
+
+	impl KeyStorage for DummyKeyStorage {
+		fn contains(&self, server_key_id: &ServerKeyId) -> bool {
+			self.keys.contains(server_key_id)
+		}
+
+		fn last_processed_block(&self) -> Result<Option<H256>, String> {
+			Ok(*self.last_processed_block.lock().unwrap())
+		}
+
+		fn update_last_processed_block(&self, block: H256) -> Result<(), String> {
+			*self.last_processed_block.lock().unwrap() = Some(block);
+			Ok(())
+		}
+	}
+}
@@ -14,16 +14,15 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::{VecDeque, HashSet};
-use std::sync::{Arc, Weak};
+use std::collections::{VecDeque, HashSet, HashMap};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use futures::{future, Future};
-use parking_lot::{RwLock, Mutex, Condvar};	
+use parking_lot::{RwLock, Mutex, Condvar};
 use ethcore::filter::Filter;
-use ethcore::client::{Client, BlockChainClient, BlockId, ChainNotify};
+use ethcore::client::BlockId;
 use ethkey::{Random, Generator, Public, Signature, sign, public_to_address};
-use ethsync::SyncProvider;
 use native_contracts::SecretStoreService;
 use bytes::Bytes;
 use hash::keccak;
@@ -34,17 +33,30 @@ use key_server_set::KeyServerSet;
 use key_server_cluster::{ClusterClient, ClusterSessionsListener, ClusterSession};
 use key_server_cluster::generation_session::SessionImpl as GenerationSession;
 use key_storage::KeyStorage;
+use trusted_client::SecretStoreChain;
 use listener::service_contract_listener::{ServiceTask, ServiceContractListenerParams};
 use {ServerKeyId, NodeKeyPair, KeyServer};
 
 /// Name of the SecretStore contract in the registry.
 const SERVICE_CONTRACT_REGISTRY_NAME: &'static str = "secretstore_service";
 
-/// Key server has been added to the set.
+/// Key server has been asked to generate a new server key.
 const SERVER_KEY_REQUESTED_EVENT_NAME: &'static [u8] = &*b"ServerKeyRequested(bytes32,uint256)";
+/// Key server has been asked to store a document key.
+const DOCUMENT_KEY_STORE_REQUESTED_EVENT_NAME: &'static [u8] = &*b"DocumentKeyStoreRequested(bytes32,address,bytes,bytes)";
+/// Key server has been asked to return a document key shadow.
+const DOCUMENT_KEY_SHADOW_RETRIEVAL_REQUESTED_EVENT_NAME: &'static [u8] = &*b"DocumentKeyShadowRetrievalRequested(bytes32,address)";
+/// Key server has been asked to produce a Schnorr signature.
+const SCHNORR_SIGNING_REQUESTED_EVENT_NAME: &'static [u8] = &*b"SchnorrSigningRequested(bytes32,address,bytes32)";
+/// Key server has been asked to produce an ECDSA signature.
+const ECDSA_SIGNING_REQUESTED_EVENT_NAME: &'static [u8] = &*b"EcdsaSigningRequested(bytes32,address,bytes32)";
 
 lazy_static! {
 	static ref SERVER_KEY_REQUESTED_EVENT_NAME_HASH: H256 = keccak(SERVER_KEY_REQUESTED_EVENT_NAME);
+	static ref DOCUMENT_KEY_STORE_REQUESTED_EVENT_NAME_HASH: H256 = keccak(DOCUMENT_KEY_STORE_REQUESTED_EVENT_NAME);
+	static ref DOCUMENT_KEY_SHADOW_RETRIEVAL_REQUESTED_EVENT_NAME_HASH: H256 = keccak(DOCUMENT_KEY_SHADOW_RETRIEVAL_REQUESTED_EVENT_NAME);
+	static ref SCHNORR_SIGNING_REQUESTED_EVENT_NAME_HASH: H256 = keccak(SCHNORR_SIGNING_REQUESTED_EVENT_NAME);
+	static ref ECDSA_SIGNING_REQUESTED_EVENT_NAME_HASH: H256 = keccak(ECDSA_SIGNING_REQUESTED_EVENT_NAME);
 }
 
 /// Service contract trait.
@@ -53,44 +65,120 @@ pub trait ServiceContract: Send + Sync {
 	fn update(&self);
 	/// Is contract installed && up-to-date (i.e. chain is synced)?
 	fn is_actual(&self) -> bool;
-	/// Read contract logs from given blocks. Returns topics of every entry.
-	fn read_logs(&self, first_block: H256, last_block: H256) -> Box<Iterator<Item=Vec<H256>>>;
-	/// Publish generated key.
-	fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask)>>;
+	/// Read contract logs up to (and including) `new_block`, reorg-safe: replays the
+	/// canonical path from the last *confirmed* block (see `confirm_last_block`), so blocks
+	/// retracted by a chain reorganization are re-scanned (their requests are effectively
+	/// re-queued) and every canonical log is emitted exactly once. If no route from the last
+	/// confirmed block to `new_block` can be computed, only `new_block` itself is scanned and the
+	/// blocks in between are not. Returns topics of every
+	/// entry; an empty result means nothing changed in the replayed range, so the caller can
+	/// skip the more expensive `read_pending_requests` poll for this cycle. Does **not**
+	/// persist `new_block` as processed - call `confirm_last_block` once the returned logs
+	/// (and the pending requests they imply) have been durably handled, so a crash between the
+	/// two never silently drops a request.
+	fn read_logs(&self, new_block: H256) -> Box<Iterator<Item=Vec<H256>>>;
+	/// Persist `block` as fully, durably processed, so a restart resumes scanning from it
+	/// instead of replaying already-handled blocks or - worse - skipping ones that were read
+	/// but never durably acted upon.
+	fn confirm_last_block(&self, block: H256) -> Result<(), String>;
+	/// Read pending service tasks: server key generation, document key store, document key
+	/// shadow retrieval and message signing requests. Each task is paired with the fee
+	/// (in wei) that the contract currently charges for that kind of request.
+	fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask, U256)>>;
+	/// Read the fee the contract currently charges for servicing a request of `task_kind`. A
+	/// standalone query for callers that want current pricing outside of a pending-requests
+	/// poll (e.g. an operator-facing RPC) - the listener itself only acts on the fee already
+	/// embedded in each `read_pending_requests` tuple, so it never calls this.
+	fn read_fee(&self, task_kind: RequestKind) -> Result<U256, String>;
 	/// Publish server key.
 	fn publish_server_key(&self, server_key_id: &ServerKeyId, server_key: &Public) -> Result<(), String>;
+	/// Publish the common point of a stored document key.
+	fn publish_document_key_common(&self, server_key_id: &ServerKeyId, requester: &Address, common_point: &Public) -> Result<(), String>;
+	/// Publish the result of a document key shadow retrieval.
+	fn publish_document_key_retrieval(&self, server_key_id: &ServerKeyId, requester: &Address, participants: H256, decrypted_secret: &Public, shadow: Bytes) -> Result<(), String>;
+	/// Publish a Schnorr/ECDSA signature.
+	fn publish_signature(&self, server_key_id: &ServerKeyId, requester: &Address, message_hash: &H256, signature: Bytes) -> Result<(), String>;
+	/// Withdraw the fees accumulated by the contract to this node's address.
+	fn drain(&self) -> Result<(), String>;
+}
+
+/// Request kind, used to enumerate pending requests of every supported task across their
+/// own `*RequestsCount`/`get*Request` contract getters, and to look up the fee the contract
+/// charges for each kind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestKind {
+	ServerKeyGeneration,
+	DocumentKeyStore,
+	DocumentKeyShadowRetrieval,
+	SchnorrSigning,
+	EcdsaSigning,
+}
+
+impl RequestKind {
+	/// All request kinds, in the order they are enumerated by `PendingRequestsIterator`.
+	fn all() -> VecDeque<RequestKind> {
+		vec![
+			RequestKind::ServerKeyGeneration,
+			RequestKind::DocumentKeyStore,
+			RequestKind::DocumentKeyShadowRetrieval,
+			RequestKind::SchnorrSigning,
+			RequestKind::EcdsaSigning,
+		].into_iter().collect()
+	}
+}
+
+/// Read the fee the contract currently charges for servicing a request of `kind`. Shared by
+/// `OnChainServiceContract::read_fee` and `PendingRequestsIterator::fee`, which only differ in
+/// how they react to a failed call.
+fn fee_for(contract: &SecretStoreService, chain: &SecretStoreChain, kind: RequestKind) -> Result<U256, String> {
+	let do_call = |a, d| future::done(chain.call_contract(BlockId::Latest, a, d));
+	match kind {
+		RequestKind::ServerKeyGeneration => contract.server_key_generation_fee(&do_call).wait(),
+		RequestKind::DocumentKeyStore => contract.document_key_store_fee(&do_call).wait(),
+		RequestKind::DocumentKeyShadowRetrieval => contract.document_key_shadow_retrieval_fee(&do_call).wait(),
+		RequestKind::SchnorrSigning => contract.schnorr_signing_fee(&do_call).wait(),
+		RequestKind::EcdsaSigning => contract.ecdsa_signing_fee(&do_call).wait(),
+	}
 }
 
 /// On-chain service contract.
 pub struct OnChainServiceContract {
-	/// Blockchain client.
-	client: Weak<Client>,
-	/// Sync provider.
-	sync: Weak<SyncProvider>,
+	/// Blockchain access, narrowed down to what this contract layer needs.
+	chain: Arc<SecretStoreChain>,
 	/// This node key pair.
 	self_key_pair: Arc<NodeKeyPair>,
 	/// Contract.
 	contract: RwLock<Arc<SecretStoreService>>,
+	/// Used to persist the last processed block across restarts, so a restarted node
+	/// resumes scanning from where it left off instead of rescanning from genesis.
+	key_storage: Arc<KeyStorage>,
 }
 
-/// Pending requests iterator.
+/// Pending requests iterator. Walks every `RequestKind` in turn, fetching that kind's
+/// length once and then its requests one by one, before moving on to the next kind.
 struct PendingRequestsIterator {
-	/// Blockchain client.
-	client: Arc<Client>,
+	/// Blockchain access.
+	chain: Arc<SecretStoreChain>,
 	/// Contract.
 	contract: Arc<SecretStoreService>,
 	/// This node key pair.
 	self_key_pair: Arc<NodeKeyPair>,
-	/// Current request index.
+	/// Request kinds not yet visited.
+	remaining_kinds: VecDeque<RequestKind>,
+	/// Request kind currently being enumerated.
+	current_kind: Option<RequestKind>,
+	/// Current request index within `current_kind`.
 	index: U256,
-	/// Requests length.
+	/// Requests length of `current_kind`.
 	length: U256,
+	/// Fee currently charged by the contract for `current_kind`.
+	current_fee: U256,
 }
 
 impl OnChainServiceContract {
 	/// Create new on-chain service contract.
-	pub fn new(client: &Arc<Client>, sync: &Arc<SyncProvider>, self_key_pair: Arc<NodeKeyPair>) -> Self {
-		let contract_addr = client.registry_address(SERVICE_CONTRACT_REGISTRY_NAME.to_owned())
+	pub fn new(chain: Arc<SecretStoreChain>, self_key_pair: Arc<NodeKeyPair>, key_storage: Arc<KeyStorage>) -> Self {
+		let contract_addr = chain.registry_address(SERVICE_CONTRACT_REGISTRY_NAME.to_owned())
 			.map(|address| {
 				trace!(target: "secretstore", "{}: installing service contract from address {}",
 					self_key_pair.public(), address);
@@ -99,102 +187,128 @@ impl OnChainServiceContract {
 			.unwrap_or_default();
 
 		OnChainServiceContract {
-			client: Arc::downgrade(client),
-			sync: Arc::downgrade(sync),
+			chain: chain,
 			self_key_pair: self_key_pair,
 			contract: RwLock::new(Arc::new(SecretStoreService::new(contract_addr))),
+			key_storage: key_storage,
 		}
 	}
 }
 
 impl ServiceContract for OnChainServiceContract {
 	fn update(&self) {
-		if let (Some(client), Some(sync)) = (self.client.upgrade(), self.sync.upgrade()) {
+		if !self.chain.is_trusted() {
 			// do nothing until synced
-			if sync.status().is_syncing(client.queue_info()) {
-				return;
-			}
+			return;
+		}
 
-			// update contract address from registry
-			let service_contract_addr = client.registry_address(SERVICE_CONTRACT_REGISTRY_NAME.to_owned()).unwrap_or_default();
-			if self.contract.read().address != service_contract_addr {
-				trace!(target: "secretstore", "{}: installing service contract from address {}",
-					self.self_key_pair.public(), service_contract_addr);
-				*self.contract.write() = Arc::new(SecretStoreService::new(service_contract_addr));
-			}
+		// update contract address from registry
+		let service_contract_addr = self.chain.registry_address(SERVICE_CONTRACT_REGISTRY_NAME.to_owned()).unwrap_or_default();
+		if self.contract.read().address != service_contract_addr {
+			trace!(target: "secretstore", "{}: installing service contract from address {}",
+				self.self_key_pair.public(), service_contract_addr);
+			*self.contract.write() = Arc::new(SecretStoreService::new(service_contract_addr));
 		}
 	}
 
 	fn is_actual(&self) -> bool {
-		self.contract.read().address != Default::default()
-			&& match (self.client.upgrade(), self.sync.upgrade()) {
-				(Some(client), Some(sync)) => !sync.status().is_syncing(client.queue_info()),
-				_ => false,
-			}
+		self.contract.read().address != Default::default() && self.chain.is_trusted()
 	}
 
-	fn read_logs(&self, first_block: H256, last_block: H256) -> Box<Iterator<Item=Vec<H256>>> {
-		let client = match self.client.upgrade() {
-			Some(client) => client,
-			None => {
-				warn!(target: "secretstore", "{}: client is offline during read_pending_requests call",
-					self.self_key_pair.public());
-				return Box::new(::std::iter::empty());
-			},
+	fn read_logs(&self, new_block: H256) -> Box<Iterator<Item=Vec<H256>>> {
+		if !self.chain.is_trusted() {
+			warn!(target: "secretstore", "{}: client is offline during read_logs call",
+				self.self_key_pair.public());
+			return Box::new(::std::iter::empty());
+		}
+
+		let last_block = self.key_storage.last_processed_block()
+			.map_err(|error| warn!(target: "secretstore", "{}: failed to read last processed block: {}",
+				self.self_key_pair.public(), error))
+			.ok()
+			.and_then(|block| block)
+			.unwrap_or_default();
+		let blocks_to_scan = if last_block == Default::default() {
+			// nothing processed yet: start scanning from the new block only
+			vec![new_block]
+		} else if last_block == new_block {
+			Vec::new()
+		} else {
+			match self.chain.tree_route(last_block, new_block) {
+				Some(route) => {
+					if !route.retracted.is_empty() {
+						warn!(target: "secretstore", "{}: {} block(s) retracted by chain reorganization; re-queueing their requests",
+							self.self_key_pair.public(), route.retracted.len());
+					}
+					route.retracted.into_iter().chain(route.enacted.into_iter()).collect()
+				},
+				None => {
+					warn!(target: "secretstore", "{}: could not compute tree route from {} to {}; resuming from new block only",
+						self.self_key_pair.public(), last_block, new_block);
+					vec![new_block]
+				},
+			}
 		};
 
-		// read server key generation requests
 		let contract_address = self.contract.read().address.clone();
-		let request_logs = client.logs(Filter {
-			from_block: BlockId::Hash(first_block),
-			to_block: BlockId::Hash(last_block),
-			address: Some(vec![contract_address]),
-			topics: vec![
-				Some(vec![*SERVER_KEY_REQUESTED_EVENT_NAME_HASH]),
-				None,
-				None,
-				None,
-			],
-			limit: None,
-		});
-
-		Box::new(request_logs.into_iter().map(|log| log.entry.topics))
-	}
-
-	fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask)>> {
-		let client = match self.client.upgrade() {
-			Some(client) => client,
-			None => {
-				warn!(target: "secretstore", "{}: client is offline during read_pending_requests call",
-					self.self_key_pair.public());
-				return Box::new(::std::iter::empty());
-			},
-		};
+		let chain = self.chain.clone();
+		let logs = blocks_to_scan.into_iter()
+			.flat_map(move |block| chain.logs(Filter {
+				from_block: BlockId::Hash(block),
+				to_block: BlockId::Hash(block),
+				address: Some(vec![contract_address]),
+				topics: vec![
+					Some(vec![
+						*SERVER_KEY_REQUESTED_EVENT_NAME_HASH,
+						*DOCUMENT_KEY_STORE_REQUESTED_EVENT_NAME_HASH,
+						*DOCUMENT_KEY_SHADOW_RETRIEVAL_REQUESTED_EVENT_NAME_HASH,
+						*SCHNORR_SIGNING_REQUESTED_EVENT_NAME_HASH,
+						*ECDSA_SIGNING_REQUESTED_EVENT_NAME_HASH,
+					]),
+					None,
+					None,
+					None,
+				],
+				limit: None,
+			}))
+			.map(|log| log.entry.topics)
+			.collect::<Vec<_>>();
+
+		Box::new(logs.into_iter())
+	}
 
-		let contract = self.contract.read();
-		let length = match contract.address == Default::default() {
-			true => 0.into(),
-			false => {
-				let do_call = |a, d| future::done(client.call_contract(BlockId::Latest, a, d));
-				contract.server_key_generation_requests_count(&do_call).wait()
-					.map_err(|error| {
-						warn!(target: "secretstore", "{}: call to server_key_generation_requests_count failed: {}",
-							self.self_key_pair.public(), error);
-						error
-					})
-					.unwrap_or_default()
-			},
-		};
+	fn confirm_last_block(&self, block: H256) -> Result<(), String> {
+		self.key_storage.update_last_processed_block(block)
+	}
+
+	fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask, U256)>> {
+		if !self.chain.is_trusted() {
+			warn!(target: "secretstore", "{}: client is offline during read_pending_requests call",
+				self.self_key_pair.public());
+			return Box::new(::std::iter::empty());
+		}
 
 		Box::new(PendingRequestsIterator {
-			client: client,
-			contract: contract.clone(),
+			chain: self.chain.clone(),
+			contract: self.contract.read().clone(),
 			self_key_pair: self.self_key_pair.clone(),
+			remaining_kinds: RequestKind::all(),
+			current_kind: None,
 			index: 0.into(),
-			length: length,
+			length: 0.into(),
+			current_fee: 0.into(),
 		})
 	}
 
+	fn read_fee(&self, task_kind: RequestKind) -> Result<U256, String> {
+		let contract = self.contract.read();
+		if contract.address == Default::default() {
+			return Ok(0.into());
+		}
+
+		fee_for(&*contract, &*self.chain, task_kind)
+	}
+
 	fn publish_server_key(&self, server_key_id: &ServerKeyId, server_key: &Public) -> Result<(), String> {
 		let server_key_hash = keccak(server_key);
 		let signed_server_key = self.self_key_pair.sign(&server_key_hash).map_err(|e| format!("{}", e))?;
@@ -208,78 +322,362 @@ impl ServiceContract for OnChainServiceContract {
 		)?;
 
 		if contract.address != Default::default() {
-			if let Some(client) = self.client.upgrade() {
-				client.transact_contract(
-					contract.address.clone(),
-					transaction_data
-				).map_err(|e| format!("{}", e))?;
-			} // else we will read this in the next refresh cycle
+			self.chain.transact_contract(contract.address.clone(), transaction_data)?;
+		} // else we will read this in the next refresh cycle
+
+		Ok(())
+	}
+
+	fn publish_document_key_common(&self, server_key_id: &ServerKeyId, requester: &Address, common_point: &Public) -> Result<(), String> {
+		let contract = self.contract.read();
+		let transaction_data = contract.encode_document_key_common_input(server_key_id.clone(),
+			requester.clone(),
+			common_point.to_vec()
+		)?;
+
+		if contract.address != Default::default() {
+			self.chain.transact_contract(contract.address.clone(), transaction_data)?;
+		}
+
+		Ok(())
+	}
+
+	fn publish_document_key_retrieval(&self, server_key_id: &ServerKeyId, requester: &Address, participants: H256, decrypted_secret: &Public, shadow: Bytes) -> Result<(), String> {
+		let contract = self.contract.read();
+		let transaction_data = contract.encode_document_key_retrieval_input(server_key_id.clone(),
+			requester.clone(),
+			participants,
+			decrypted_secret.to_vec(),
+			shadow
+		)?;
+
+		if contract.address != Default::default() {
+			self.chain.transact_contract(contract.address.clone(), transaction_data)?;
+		}
+
+		Ok(())
+	}
+
+	fn publish_signature(&self, server_key_id: &ServerKeyId, requester: &Address, message_hash: &H256, signature: Bytes) -> Result<(), String> {
+		let contract = self.contract.read();
+		let transaction_data = contract.encode_signature_input(server_key_id.clone(),
+			requester.clone(),
+			message_hash.clone(),
+			signature
+		)?;
+
+		if contract.address != Default::default() {
+			self.chain.transact_contract(contract.address.clone(), transaction_data)?;
 		}
 
 		Ok(())
 	}
+
+	fn drain(&self) -> Result<(), String> {
+		let contract = self.contract.read();
+		if contract.address == Default::default() {
+			return Err("service contract is not installed".into());
+		}
+
+		let transaction_data = contract.encode_drain_input()?;
+		self.chain.transact_contract(contract.address.clone(), transaction_data)
+	}
 }
 
-impl Iterator for PendingRequestsIterator {
-	type Item = (bool, ServiceTask);
+/// Extract the server key id that a pending `ServiceTask` is about, so that
+/// `ConnectedServiceContracts` can remember which underlying contract to route a task's
+/// eventual `publish_*` call back to.
+fn service_task_key_id(task: &ServiceTask) -> &ServerKeyId {
+	match *task {
+		ServiceTask::GenerateServerKey(ref server_key_id, _) => server_key_id,
+		ServiceTask::StoreDocumentKey(ref server_key_id, _, _, _) => server_key_id,
+		ServiceTask::RetrieveShadowDocumentKey(ref server_key_id, _) => server_key_id,
+		ServiceTask::SchnorrSignMessage(ref server_key_id, _, _) => server_key_id,
+		ServiceTask::EcdsaSignMessage(ref server_key_id, _, _) => server_key_id,
+	}
+}
+
+/// Aggregates several independent `ServiceContract` deployments behind a single instance,
+/// letting one key server serve multiple on-chain SecretStore contracts at once (e.g. while
+/// migrating between contract versions). `update`/`is_actual` fan out to every contract and
+/// `read_logs`/`read_pending_requests` chain their results; every `publish_*` call is routed
+/// to the contracts last seen reporting the originating request as unconfirmed, or to every
+/// contract when no such contract is known.
+pub struct ConnectedServiceContracts {
+	/// The aggregated contracts, in the order they are polled.
+	contracts: Vec<Arc<ServiceContract>>,
+	/// Indices of the contracts currently reporting an *unconfirmed* pending request for a
+	/// given server key, rebuilt from scratch on every `read_pending_requests` call. During a
+	/// migration the same key can legitimately be unconfirmed on more than one contract at
+	/// once, so this tracks every such contract rather than only the most recently seen one.
+	origins: Mutex<HashMap<ServerKeyId, HashSet<usize>>>,
+}
 
-	fn next(&mut self) -> Option<(bool, ServiceTask)> {
-		if self.index >= self.length {
-			return None;
+impl ConnectedServiceContracts {
+	/// Create a new aggregating service contract from its constituent deployments.
+	pub fn new(contracts: Vec<Arc<ServiceContract>>) -> Self {
+		ConnectedServiceContracts {
+			contracts: contracts,
+			origins: Mutex::new(HashMap::new()),
 		}
-		self.index = self.index + 1.into();
-
-		let do_call = |a, d| future::done(self.client.call_contract(BlockId::Latest, a, d));
-		let key_generation_request = self.contract.get_server_key_generation_request(&do_call,
-			public_to_address(self.self_key_pair.public()),
-			(self.index - 1.into()).clone().into()).wait();
-		let (server_key_id, threshold, is_confirmed) = match key_generation_request {
-			Ok((server_key_id, threshold, is_confirmed)) => {
-				(server_key_id, threshold, is_confirmed)
-			},
-			Err(error) => {
-				warn!(target: "secretstore", "{}: call to get_server_key_generation_request failed: {}",
-					self.self_key_pair.public(), error);
-				return None;
-			},
+	}
+
+	/// The contracts currently reporting an unconfirmed pending request for `server_key_id`, if
+	/// any are known; otherwise every configured contract (e.g. after a restart, before
+	/// `read_pending_requests` has re-observed the request).
+	fn candidate_contracts(&self, server_key_id: &ServerKeyId) -> Vec<Arc<ServiceContract>> {
+		match self.origins.lock().get(server_key_id) {
+			Some(indices) if !indices.is_empty() =>
+				indices.iter().filter_map(|&index| self.contracts.get(index)).cloned().collect(),
+			_ => self.contracts.clone(),
+		}
+	}
+
+	/// Publish to every candidate contract for `server_key_id` (see `candidate_contracts`),
+	/// succeeding if any of them accepts the publication. Contracts are never skipped after the
+	/// first success: `publish_*` returning `Ok` only means the transaction was submitted
+	/// locally, not accepted on-chain, so stopping early risks never reaching the contract that
+	/// actually holds the request. Submitting to a contract that does not hold it is harmless -
+	/// it is simply rejected on-chain.
+	fn publish<F: Fn(&ServiceContract) -> Result<(), String>>(&self, server_key_id: &ServerKeyId, publish: F) -> Result<(), String> {
+		let candidates = self.candidate_contracts(server_key_id);
+		if candidates.is_empty() {
+			return Err("no service contract is configured".to_owned());
+		}
+
+		let mut result = Err("no service contract accepted the publication".to_owned());
+		for contract in candidates {
+			if publish(&*contract).is_ok() {
+				result = Ok(());
+			}
+		}
+		result
+	}
+}
+
+impl ServiceContract for ConnectedServiceContracts {
+	fn update(&self) {
+		for contract in &self.contracts {
+			contract.update();
+		}
+	}
+
+	fn is_actual(&self) -> bool {
+		self.contracts.iter().any(|contract| contract.is_actual())
+	}
+
+	fn read_logs(&self, new_block: H256) -> Box<Iterator<Item=Vec<H256>>> {
+		// Only poll contracts that are actually installed and synced - an inactive contract
+		// (e.g. not yet deployed during a migration) has nothing to read and would otherwise
+		// be queried, and warn about being offline, on every single cycle indefinitely.
+		let logs = self.contracts.iter()
+			.filter(|contract| contract.is_actual())
+			.flat_map(|contract| contract.read_logs(new_block))
+			.collect::<Vec<_>>();
+		Box::new(logs.into_iter())
+	}
+
+	fn confirm_last_block(&self, block: H256) -> Result<(), String> {
+		// Every underlying contract tracks its own last-processed block, so a given block has
+		// to be confirmed on all of them, not just the ones `candidate_contracts` would route a publish to.
+		let mut last_error = Ok(());
+		for contract in &self.contracts {
+			if let Err(error) = contract.confirm_last_block(block) {
+				last_error = Err(error);
+			}
+		}
+		last_error
+	}
+
+	fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask, U256)>> {
+		// As in read_logs: skip contracts that are not actually installed and synced, so an
+		// inactive contract does not pay for up to 5 *_requests_count RPCs every poll cycle.
+		let requests = self.contracts.iter().enumerate()
+			.filter(|&(_, contract)| contract.is_actual())
+			.flat_map(|(index, contract)| contract.read_pending_requests()
+				.map(|(is_confirmed, task, fee)| (index, is_confirmed, task, fee))
+				.collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+
+		// Rebuilt from scratch every call: a confirmed request no longer needs routing, and an
+		// unconfirmed sighting that disappears (e.g. the request was confirmed or cancelled)
+		// must stop being a candidate too.
+		let mut origins: HashMap<ServerKeyId, HashSet<usize>> = HashMap::new();
+		for &(index, is_confirmed, ref task, _) in &requests {
+			if !is_confirmed {
+				origins.entry(service_task_key_id(task).clone()).or_insert_with(HashSet::new).insert(index);
+			}
+		}
+		*self.origins.lock() = origins;
+
+		Box::new(requests.into_iter().map(|(_, is_confirmed, task, fee)| (is_confirmed, task, fee)))
+	}
+
+	fn read_fee(&self, task_kind: RequestKind) -> Result<U256, String> {
+		self.contracts.iter()
+			.find(|contract| contract.is_actual())
+			.ok_or_else(|| "no actual service contract is configured".to_owned())
+			.and_then(|contract| contract.read_fee(task_kind))
+	}
+
+	fn publish_server_key(&self, server_key_id: &ServerKeyId, server_key: &Public) -> Result<(), String> {
+		self.publish(server_key_id, |contract| contract.publish_server_key(server_key_id, server_key))
+	}
+
+	fn publish_document_key_common(&self, server_key_id: &ServerKeyId, requester: &Address, common_point: &Public) -> Result<(), String> {
+		self.publish(server_key_id, |contract| contract.publish_document_key_common(server_key_id, requester, common_point))
+	}
+
+	fn publish_document_key_retrieval(&self, server_key_id: &ServerKeyId, requester: &Address, participants: H256, decrypted_secret: &Public, shadow: Bytes) -> Result<(), String> {
+		self.publish(server_key_id, |contract| contract.publish_document_key_retrieval(server_key_id, requester, participants, decrypted_secret, shadow.clone()))
+	}
+
+	fn publish_signature(&self, server_key_id: &ServerKeyId, requester: &Address, message_hash: &H256, signature: Bytes) -> Result<(), String> {
+		self.publish(server_key_id, |contract| contract.publish_signature(server_key_id, requester, message_hash, signature.clone()))
+	}
+
+	fn drain(&self) -> Result<(), String> {
+		let mut last_error = Ok(());
+		for contract in &self.contracts {
+			if let Err(error) = contract.drain() {
+				last_error = Err(error);
+			}
+		}
+		last_error
+	}
+}
+
+impl PendingRequestsIterator {
+	/// Fetch the requests count of `kind` from the contract.
+	fn requests_count(&self, kind: RequestKind) -> U256 {
+		let do_call = |a, d| future::done(self.chain.call_contract(BlockId::Latest, a, d));
+		let result = match kind {
+			RequestKind::ServerKeyGeneration => self.contract.server_key_generation_requests_count(&do_call).wait(),
+			RequestKind::DocumentKeyStore => self.contract.document_key_store_requests_count(&do_call).wait(),
+			RequestKind::DocumentKeyShadowRetrieval => self.contract.document_key_shadow_retrieval_requests_count(&do_call).wait(),
+			RequestKind::SchnorrSigning => self.contract.schnorr_signing_requests_count(&do_call).wait(),
+			RequestKind::EcdsaSigning => self.contract.ecdsa_signing_requests_count(&do_call).wait(),
 		};
 
-		Some((is_confirmed, ServiceTask::GenerateServerKey(server_key_id, threshold.into())))
+		result.map_err(|error| {
+			warn!(target: "secretstore", "{}: call to read pending requests count failed: {}",
+				self.self_key_pair.public(), error);
+			error
+		}).unwrap_or_default()
+	}
+
+	/// Fetch the fee currently charged by the contract for requests of `kind`.
+	fn fee(&self, kind: RequestKind) -> U256 {
+		fee_for(&*self.contract, &*self.chain, kind).map_err(|error| {
+			warn!(target: "secretstore", "{}: call to read request fee failed: {}",
+				self.self_key_pair.public(), error);
+			error
+		}).unwrap_or_default()
+	}
+
+	/// Fetch a single request of `kind` at `index` from the contract.
+	fn request_at(&self, kind: RequestKind, index: U256) -> Option<(bool, ServiceTask)> {
+		let do_call = |a, d| future::done(self.chain.call_contract(BlockId::Latest, a, d));
+		let self_address = public_to_address(self.self_key_pair.public());
+
+		match kind {
+			RequestKind::ServerKeyGeneration => self.contract.get_server_key_generation_request(&do_call, self_address, index.clone().into()).wait()
+				.map(|(server_key_id, threshold, is_confirmed)|
+					(is_confirmed, ServiceTask::GenerateServerKey(server_key_id, threshold.into())))
+				.map_err(|error| warn!(target: "secretstore", "{}: call to get_server_key_generation_request failed: {}",
+					self.self_key_pair.public(), error))
+				.ok(),
+			RequestKind::DocumentKeyStore => self.contract.get_document_key_store_request(&do_call, self_address, index.clone().into()).wait()
+				.map(|(server_key_id, author, common_point, encrypted_point, is_confirmed)|
+					(is_confirmed, ServiceTask::StoreDocumentKey(server_key_id, author, common_point, encrypted_point)))
+				.map_err(|error| warn!(target: "secretstore", "{}: call to get_document_key_store_request failed: {}",
+					self.self_key_pair.public(), error))
+				.ok(),
+			RequestKind::DocumentKeyShadowRetrieval => self.contract.get_document_key_shadow_retrieval_request(&do_call, self_address, index.clone().into()).wait()
+				.map(|(server_key_id, requester, is_confirmed)|
+					(is_confirmed, ServiceTask::RetrieveShadowDocumentKey(server_key_id, requester)))
+				.map_err(|error| warn!(target: "secretstore", "{}: call to get_document_key_shadow_retrieval_request failed: {}",
+					self.self_key_pair.public(), error))
+				.ok(),
+			RequestKind::SchnorrSigning => self.contract.get_schnorr_signing_request(&do_call, self_address, index.clone().into()).wait()
+				.map(|(server_key_id, requester, message_hash, is_confirmed)|
+					(is_confirmed, ServiceTask::SchnorrSignMessage(server_key_id, requester, message_hash)))
+				.map_err(|error| warn!(target: "secretstore", "{}: call to get_schnorr_signing_request failed: {}",
+					self.self_key_pair.public(), error))
+				.ok(),
+			RequestKind::EcdsaSigning => self.contract.get_ecdsa_signing_request(&do_call, self_address, index.clone().into()).wait()
+				.map(|(server_key_id, requester, message_hash, is_confirmed)|
+					(is_confirmed, ServiceTask::EcdsaSignMessage(server_key_id, requester, message_hash)))
+				.map_err(|error| warn!(target: "secretstore", "{}: call to get_ecdsa_signing_request failed: {}",
+					self.self_key_pair.public(), error))
+				.ok(),
+		}
+	}
+}
+
+impl Iterator for PendingRequestsIterator {
+	type Item = (bool, ServiceTask, U256);
+
+	fn next(&mut self) -> Option<(bool, ServiceTask, U256)> {
+		loop {
+			if self.index >= self.length {
+				self.current_kind = self.remaining_kinds.pop_front();
+				let kind = match self.current_kind {
+					Some(kind) => kind,
+					None => return None,
+				};
+
+				self.index = 0.into();
+				self.length = self.requests_count(kind);
+				// Only worth a contract call when there is at least one request of this kind
+				// pending; otherwise every poll cycle would pay for 5 RPCs that go unused.
+				self.current_fee = if self.length > 0.into() {
+					self.fee(kind)
+				} else {
+					0.into()
+				};
+				continue;
+			}
+
+			let kind = self.current_kind.expect("current_kind is Some whenever index < length; qed");
+			let index = self.index;
+			self.index = self.index + 1.into();
+
+			match self.request_at(kind, index) {
+				Some((is_confirmed, task)) => return Some((is_confirmed, task, self.current_fee)),
+				None => continue,
+			}
+		}
 	}
 }
 
 #[cfg(test)]
 pub mod tests {
-	use std::collections::{VecDeque, HashSet};
-	use std::sync::{Arc, Weak};
-	use std::sync::atomic::{AtomicUsize, Ordering};
-	use std::thread;
-	use futures::{future, Future};
-	use parking_lot::{RwLock, Mutex, Condvar};	
-	use ethcore::filter::Filter;
-	use ethcore::client::{Client, BlockChainClient, BlockId, ChainNotify};
-	use ethkey::{Random, Generator, Public, Signature, sign, public_to_address};
-	use ethsync::SyncProvider;
-	use native_contracts::SecretStoreService;
-	use bytes::Bytes;
-	use hash::keccak;
+	use std::sync::Arc;
+	use parking_lot::Mutex;
+	use ethkey::Public;
 	use bigint::hash::H256;
-	use bigint::prelude::U256;
+	use bytes::Bytes;
 	use util::Address;
-	use key_server_set::KeyServerSet;
-	use key_server_cluster::{ClusterClient, ClusterSessionsListener, ClusterSession};
-	use key_server_cluster::generation_session::SessionImpl as GenerationSession;
+	use bigint::prelude::U256;
 	use key_storage::KeyStorage;
-	use listener::service_contract_listener::{ServiceTask, ServiceContractListenerParams};
-	use {ServerKeyId, NodeKeyPair, KeyServer};
-	use super::ServiceContract;
+	use listener::service_contract_listener::ServiceTask;
+	use ServerKeyId;
+	use super::{ServiceContract, ConnectedServiceContracts, RequestKind};
 
 	#[derive(Default)]
 	pub struct DummyServiceContract {
 		pub is_actual: bool,
 		pub logs: Vec<Vec<H256>>,
-		pub pending_requests: Vec<(bool, ServiceTask)>,
+		pub pending_requests: Vec<(bool, ServiceTask, U256)>,
+		pub fee: U256,
+		pub confirmed_block: Mutex<Option<H256>>,
 		pub published_keys: Mutex<Vec<(ServerKeyId, Public)>>,
+		pub published_key_commons: Mutex<Vec<(ServerKeyId, Address, Public)>>,
+		pub published_key_retrievals: Mutex<Vec<(ServerKeyId, Address, Public)>>,
+		pub published_signatures: Mutex<Vec<(ServerKeyId, Address, H256)>>,
+		pub drain_calls: Mutex<usize>,
+		pub drain_error: Option<String>,
 	}
 
 	impl ServiceContract for DummyServiceContract {
@@ -290,17 +688,94 @@ pub mod tests {
 			self.is_actual
 		}
 
-		fn read_logs(&self, first_block: H256, last_block: H256) -> Box<Iterator<Item=Vec<H256>>> {
+		fn read_logs(&self, _new_block: H256) -> Box<Iterator<Item=Vec<H256>>> {
 			Box::new(self.logs.clone().into_iter())
 		}
 
-		fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask)>> {
+		fn confirm_last_block(&self, block: H256) -> Result<(), String> {
+			*self.confirmed_block.lock() = Some(block);
+			Ok(())
+		}
+
+		fn read_pending_requests(&self) -> Box<Iterator<Item=(bool, ServiceTask, U256)>> {
 			Box::new(self.pending_requests.clone().into_iter())
 		}
 
+		fn read_fee(&self, _task_kind: RequestKind) -> Result<U256, String> {
+			Ok(self.fee)
+		}
+
 		fn publish_server_key(&self, server_key_id: &ServerKeyId, server_key: &Public) -> Result<(), String> {
 			self.published_keys.lock().push((server_key_id.clone(), server_key.clone()));
 			Ok(())
 		}
+
+		fn publish_document_key_common(&self, server_key_id: &ServerKeyId, requester: &Address, common_point: &Public) -> Result<(), String> {
+			self.published_key_commons.lock().push((server_key_id.clone(), requester.clone(), common_point.clone()));
+			Ok(())
+		}
+
+		fn publish_document_key_retrieval(&self, server_key_id: &ServerKeyId, requester: &Address, _participants: H256, decrypted_secret: &Public, _shadow: Bytes) -> Result<(), String> {
+			self.published_key_retrievals.lock().push((server_key_id.clone(), requester.clone(), decrypted_secret.clone()));
+			Ok(())
+		}
+
+		fn publish_signature(&self, server_key_id: &ServerKeyId, requester: &Address, message_hash: &H256, _signature: Bytes) -> Result<(), String> {
+			self.published_signatures.lock().push((server_key_id.clone(), requester.clone(), message_hash.clone()));
+			Ok(())
+		}
+
+		fn drain(&self) -> Result<(), String> {
+			*self.drain_calls.lock() += 1;
+			match self.drain_error {
+				Some(ref error) => Err(error.clone()),
+				None => Ok(()),
+			}
+		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn publish_broadcasts_to_all_contracts_when_origin_is_unknown() {
+		let server_key_id: ServerKeyId = H256::from(1);
+		let contract0 = Arc::new(DummyServiceContract::default());
+		let contract1 = Arc::new(DummyServiceContract::default());
+		let connected = ConnectedServiceContracts::new(vec![contract0.clone() as Arc<ServiceContract>, contract1.clone() as Arc<ServiceContract>]);
+
+		// no read_pending_requests call yet => origin is unknown => broadcast to every contract
+		connected.publish_server_key(&server_key_id, &Public::default()).unwrap();
+
+		assert_eq!(contract0.published_keys.lock().len(), 1);
+		assert_eq!(contract1.published_keys.lock().len(), 1);
+	}
+
+	#[test]
+	fn publish_only_targets_contracts_still_reporting_the_request_unconfirmed() {
+		let server_key_id: ServerKeyId = H256::from(1);
+		let contract0 = Arc::new(DummyServiceContract::default());
+		let mut contract1_inner = DummyServiceContract::default();
+		contract1_inner.is_actual = true;
+		contract1_inner.pending_requests = vec![(false, ServiceTask::GenerateServerKey(server_key_id.clone(), 1), 0.into())];
+		let contract1 = Arc::new(contract1_inner);
+		let connected = ConnectedServiceContracts::new(vec![contract0.clone() as Arc<ServiceContract>, contract1.clone() as Arc<ServiceContract>]);
+
+		assert_eq!(connected.read_pending_requests().count(), 1);
+		connected.publish_server_key(&server_key_id, &Public::default()).unwrap();
+
+		assert_eq!(contract0.published_keys.lock().len(), 0);
+		assert_eq!(contract1.published_keys.lock().len(), 1);
+	}
+
+	#[test]
+	fn drain_fans_out_to_every_contract_and_reports_any_failure() {
+		let contract0 = Arc::new(DummyServiceContract::default());
+		let mut contract1_inner = DummyServiceContract::default();
+		contract1_inner.drain_error = Some("not installed".into());
+		let contract1 = Arc::new(contract1_inner);
+		let connected = ConnectedServiceContracts::new(vec![contract0.clone() as Arc<ServiceContract>, contract1.clone() as Arc<ServiceContract>]);
+
+		// contract1 failing to drain does not stop contract0 from being drained too
+		assert!(connected.drain().is_err());
+		assert_eq!(*contract0.drain_calls.lock(), 1);
+		assert_eq!(*contract1.drain_calls.lock(), 1);
+	}
+}
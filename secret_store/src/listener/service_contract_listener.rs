@@ -0,0 +1,163 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use bigint::hash::H256;
+use bigint::prelude::U256;
+use ethkey::Public;
+use util::Address;
+use key_server_cluster::ClusterClient;
+use key_server_set::KeyServerSet;
+use listener::service_contract::ServiceContract;
+use {ServerKeyId, NodeKeyPair};
+
+/// A service task that needs to be turned into a key server cluster session.
+#[derive(Debug, Clone)]
+pub enum ServiceTask {
+	/// Generate a new server key (server_key_id, threshold).
+	GenerateServerKey(ServerKeyId, usize),
+	/// Store a document key (server_key_id, author, common_point, encrypted_point).
+	StoreDocumentKey(ServerKeyId, Address, Public, Public),
+	/// Retrieve a document key shadow (server_key_id, requester).
+	RetrieveShadowDocumentKey(ServerKeyId, Address),
+	/// Produce a Schnorr signature (server_key_id, requester, message_hash).
+	SchnorrSignMessage(ServerKeyId, Address, H256),
+	/// Produce an ECDSA signature (server_key_id, requester, message_hash).
+	EcdsaSignMessage(ServerKeyId, Address, H256),
+}
+
+/// Distinguishes the two signing schemes that share `ClusterClient::new_signing_session`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SigningSessionType {
+	/// Schnorr signature, used by the Substrate-style signing sessions.
+	Schnorr,
+	/// ECDSA signature.
+	Ecdsa,
+}
+
+/// Construction parameters for `ServiceContractListener`.
+pub struct ServiceContractListenerParams {
+	/// Service contract (possibly an aggregate of several on-chain deployments).
+	pub contract: Arc<ServiceContract>,
+	/// Key server cluster client, used to start sessions for pending requests.
+	pub cluster: Arc<ClusterClient>,
+	/// This node key pair.
+	pub self_key_pair: Arc<NodeKeyPair>,
+	/// Key server set.
+	pub key_server_set: Arc<KeyServerSet>,
+	/// Minimum fee (in wei) this node is willing to service a request for. Requests charging
+	/// less are skipped, rather than serviced at a loss; `0` services every request.
+	pub min_fee: U256,
+}
+
+/// Watches the service contract for pending requests and dispatches each of them to the key
+/// server cluster as a session. The listener does not publish session results back to the
+/// contract: it never calls `ServiceContract::publish_*`.
+pub struct ServiceContractListener {
+	/// Service contract.
+	contract: Arc<ServiceContract>,
+	/// Key server cluster client.
+	cluster: Arc<ClusterClient>,
+	/// This node key pair.
+	self_key_pair: Arc<NodeKeyPair>,
+	/// Minimum fee this node services a request for; see `ServiceContractListenerParams::min_fee`.
+	min_fee: U256,
+}
+
+impl ServiceContractListener {
+	/// Create new service contract listener.
+	pub fn new(params: ServiceContractListenerParams) -> Self {
+		ServiceContractListener {
+			contract: params.contract,
+			cluster: params.cluster,
+			self_key_pair: params.self_key_pair,
+			min_fee: params.min_fee,
+		}
+	}
+
+	/// Called whenever the chain advances to `new_block`: read contract logs since the last
+	/// confirmed block and, only if the reorg-safe replay actually saw something change, poll
+	/// and dispatch pending requests to the cluster.
+	pub fn process_service_contract(&self, new_block: H256) {
+		if !self.contract.is_actual() {
+			return;
+		}
+
+		let mut has_new_logs = false;
+		for log_topics in self.contract.read_logs(new_block) {
+			has_new_logs = true;
+			trace!(target: "secretstore", "{}: received service contract event with topics {:?}",
+				self.self_key_pair.public(), log_topics);
+		}
+
+		// Nothing changed in the replayed range (including any retracted blocks): there is
+		// nothing new for `read_pending_requests`'s live poll to find, so skip it rather than
+		// paying for a full `*_requests_count`/`get*_request` sweep every cycle for no reason.
+		if has_new_logs {
+			for (is_confirmed, task, fee) in self.contract.read_pending_requests() {
+				if is_confirmed {
+					continue;
+				}
+
+				if fee < self.min_fee {
+					trace!(target: "secretstore", "{}: skipping underpaid service task (fee {}, minimum {})",
+						self.self_key_pair.public(), fee, self.min_fee);
+					continue;
+				}
+
+				if let Err(error) = self.process_service_task(task) {
+					warn!(target: "secretstore", "{}: failed to process service task: {}",
+						self.self_key_pair.public(), error);
+				}
+			}
+		}
+
+		// Only mark `new_block` as processed once every request implied by this cycle's logs
+		// has been handed to the cluster above - persisting any earlier (e.g. from `read_logs`
+		// itself) would let a crash between persisting and dispatching silently drop requests.
+		// A request whose dispatch failed is only logged; the block is still marked processed.
+		if let Err(error) = self.contract.confirm_last_block(new_block) {
+			warn!(target: "secretstore", "{}: failed to confirm last processed block: {}",
+				self.self_key_pair.public(), error);
+		}
+	}
+
+	/// Dispatch a single pending task to the key server cluster.
+	fn process_service_task(&self, task: ServiceTask) -> Result<(), String> {
+		match task {
+			ServiceTask::GenerateServerKey(server_key_id, threshold) => self.cluster
+				.new_generation_session(server_key_id, threshold)
+				.map(|_| ())
+				.map_err(|error| format!("{}", error)),
+			ServiceTask::StoreDocumentKey(server_key_id, author, common_point, encrypted_point) => self.cluster
+				.new_encryption_session(server_key_id, author, common_point, encrypted_point)
+				.map(|_| ())
+				.map_err(|error| format!("{}", error)),
+			ServiceTask::RetrieveShadowDocumentKey(server_key_id, requester) => self.cluster
+				.new_decryption_session(server_key_id, requester)
+				.map(|_| ())
+				.map_err(|error| format!("{}", error)),
+			ServiceTask::SchnorrSignMessage(server_key_id, requester, message_hash) => self.cluster
+				.new_signing_session(server_key_id, requester, message_hash, SigningSessionType::Schnorr)
+				.map(|_| ())
+				.map_err(|error| format!("{}", error)),
+			ServiceTask::EcdsaSignMessage(server_key_id, requester, message_hash) => self.cluster
+				.new_signing_session(server_key_id, requester, message_hash, SigningSessionType::Ecdsa)
+				.map(|_| ())
+				.map_err(|error| format!("{}", error)),
+		}
+	}
+}
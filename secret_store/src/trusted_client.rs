@@ -0,0 +1,188 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Weak};
+use ethcore::filter::Filter;
+use ethcore::client::{Client, BlockChainClient, BlockId};
+use ethcore::log_entry::LocalizedLogEntry;
+use ethsync::SyncProvider;
+use bytes::Bytes;
+use bigint::hash::H256;
+use util::Address;
+
+/// Blockchain operations that the SecretStore service contract layer needs. Narrowing the
+/// surface down to this trait (instead of depending on `Client`/`SyncProvider` directly)
+/// lets the contract layer be unit-tested with a mock chain.
+pub trait SecretStoreChain: Send + Sync {
+	/// Is the underlying client alive and fully synced?
+	fn is_trusted(&self) -> bool;
+	/// Resolve a contract address from the on-chain registry.
+	fn registry_address(&self, name: String) -> Option<Address>;
+	/// Call a contract method at the given block.
+	fn call_contract(&self, block: BlockId, contract_address: Address, data: Bytes) -> Result<Bytes, String>;
+	/// Submit a signed contract transaction from this node.
+	fn transact_contract(&self, contract_address: Address, data: Bytes) -> Result<(), String>;
+	/// Read logs matching the given filter.
+	fn logs(&self, filter: Filter) -> Vec<LocalizedLogEntry>;
+	/// Resolve a block hash for the given block id.
+	fn block_hash(&self, block_id: BlockId) -> Option<H256>;
+	/// Compute the canonical route between two blocks, so that callers can replay
+	/// `retracted` blocks (no longer on the canonical chain) before `enacted` ones
+	/// (newly canonical) when recovering from a chain reorganization.
+	fn tree_route(&self, from: H256, to: H256) -> Option<ChainRoute>;
+}
+
+/// Canonical route between two blocks of a `tree_route` call.
+pub struct ChainRoute {
+	/// Blocks that are no longer on the canonical chain, oldest first.
+	pub retracted: Vec<H256>,
+	/// Blocks that are now on the canonical chain, oldest first.
+	pub enacted: Vec<H256>,
+}
+
+/// `SecretStoreChain` implementation, backed by a weakly-held `Client`/`SyncProvider` pair.
+/// Holding the client/sync weakly (rather than strongly) lets the node shut down the client
+/// without the SecretStore service keeping it alive.
+pub struct TrustedClient {
+	/// Blockchain client.
+	client: Weak<Client>,
+	/// Sync provider.
+	sync: Weak<SyncProvider>,
+}
+
+impl TrustedClient {
+	/// Create new trusted client.
+	pub fn new(client: &Arc<Client>, sync: &Arc<SyncProvider>) -> Self {
+		TrustedClient {
+			client: Arc::downgrade(client),
+			sync: Arc::downgrade(sync),
+		}
+	}
+
+	/// Get the underlying client, but only if it is still alive and fully synced. Every
+	/// operation that should be skipped while the node is offline or catching up goes
+	/// through this single guard, instead of each caller repeating the check.
+	fn trusted_client(&self) -> Option<Arc<Client>> {
+		match (self.client.upgrade(), self.sync.upgrade()) {
+			(Some(client), Some(sync)) => match sync.status().is_syncing(client.queue_info()) {
+				false => Some(client),
+				true => None,
+			},
+			_ => None,
+		}
+	}
+}
+
+impl SecretStoreChain for TrustedClient {
+	fn is_trusted(&self) -> bool {
+		self.trusted_client().is_some()
+	}
+
+	fn registry_address(&self, name: String) -> Option<Address> {
+		self.client.upgrade().and_then(|client| client.registry_address(name))
+	}
+
+	fn call_contract(&self, block: BlockId, contract_address: Address, data: Bytes) -> Result<Bytes, String> {
+		self.trusted_client()
+			.ok_or_else(|| "trusted client is not available".to_owned())
+			.and_then(|client| client.call_contract(block, contract_address, data))
+	}
+
+	fn transact_contract(&self, contract_address: Address, data: Bytes) -> Result<(), String> {
+		self.client.upgrade()
+			.ok_or_else(|| "trusted client is not available".to_owned())
+			.and_then(|client| client.transact_contract(contract_address, data)
+				.map_err(|e| format!("{}", e)))
+	}
+
+	fn logs(&self, filter: Filter) -> Vec<LocalizedLogEntry> {
+		self.client.upgrade()
+			.map(|client| client.logs(filter))
+			.unwrap_or_default()
+	}
+
+	fn block_hash(&self, block_id: BlockId) -> Option<H256> {
+		self.client.upgrade().and_then(|client| client.block_hash(block_id))
+	}
+
+	fn tree_route(&self, from: H256, to: H256) -> Option<ChainRoute> {
+		self.client.upgrade().and_then(|client| client.tree_route(&from, &to)).map(|route| {
+			// `route.blocks[..route.index]` is newest-first (it walks up from `from` towards
+			// the common ancestor), so it has to be reversed to match the "oldest first"
+			// contract that both fields of `ChainRoute` promise.
+			ChainRoute {
+				retracted: route.blocks[..route.index].iter().rev().cloned().collect(),
+				enacted: route.blocks[route.index..].to_vec(),
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use std::sync::Mutex;
+	use ethcore::filter::Filter;
+	use ethcore::client::BlockId;
+	use ethcore::log_entry::LocalizedLogEntry;
+	use bytes::Bytes;
+	use bigint::hash::H256;
+	use util::Address;
+	use super::{SecretStoreChain, ChainRoute};
+
+	/// Chain mock, used to unit-test the contract layer without a full `Client`.
+	#[derive(Default)]
+	pub struct DummySecretStoreChain {
+		pub is_trusted: bool,
+		pub registry_address: Option<Address>,
+		pub logs: Vec<LocalizedLogEntry>,
+		pub transactions: Mutex<Vec<(Address, Bytes)>>,
+		pub tree_routes: Mutex<::std::collections::HashMap<(H256, H256), (Vec<H256>, Vec<H256>)>>,
+	}
+
+	impl SecretStoreChain for DummySecretStoreChain {
+		fn is_trusted(&self) -> bool {
+			self.is_trusted
+		}
+
+		fn registry_address(&self, _name: String) -> Option<Address> {
+			self.registry_address.clone()
+		}
+
+		fn call_contract(&self, _block: BlockId, _contract_address: Address, _data: Bytes) -> Result<Bytes, String> {
+			Err("DummySecretStoreChain does not support call_contract".into())
+		}
+
+		fn transact_contract(&self, contract_address: Address, data: Bytes) -> Result<(), String> {
+			self.transactions.lock().unwrap().push((contract_address, data));
+			Ok(())
+		}
+
+		fn logs(&self, _filter: Filter) -> Vec<LocalizedLogEntry> {
+			self.logs.clone()
+		}
+
+		fn block_hash(&self, _block_id: BlockId) -> Option<H256> {
+			None
+		}
+
+		fn tree_route(&self, from: H256, to: H256) -> Option<ChainRoute> {
+			self.tree_routes.lock().unwrap().get(&(from, to)).map(|&(ref retracted, ref enacted)| ChainRoute {
+				retracted: retracted.clone(),
+				enacted: enacted.clone(),
+			})
+		}
+	}
+}